@@ -0,0 +1,33 @@
+use point_cloud_viewer::octree;
+use std::path::PathBuf;
+use std::process;
+
+/// Loads an octree and reports any corrupt or mismatched nodes it finds.
+///
+/// Usage: check_octree <octree-directory>
+fn main() {
+    let octree_directory = match std::env::args().nth(1) {
+        Some(arg) => PathBuf::from(arg),
+        None => {
+            eprintln!("Usage: check_octree <octree-directory>");
+            process::exit(1);
+        }
+    };
+
+    let octree = octree::Octree::from_directory(&octree_directory)
+        .unwrap_or_else(|err| panic!("Could not open octree at {:?}: {}", octree_directory, err));
+
+    let report = octree::check::check(&octree);
+    println!(
+        "Checked {} node(s), {} corrupt",
+        report.num_nodes_checked,
+        report.corrupt_nodes.len()
+    );
+    for (node_id, errors) in &report.corrupt_nodes {
+        println!("  {:?}: {:?}", node_id, errors);
+    }
+
+    if !report.is_ok() {
+        process::exit(1);
+    }
+}
@@ -1,16 +1,25 @@
 use crate::errors::*;
 use crate::math::PointCulling;
 use crate::math::{AllPoints, Isometry3, Obb, OrientedBeam};
+use crate::octree::io_engine::{Block, IoEngine, NodeLocation, SyncIoEngine};
+use crate::octree::nearest_neighbor;
 use crate::octree::{self, Octree};
 use crate::{LayerData, Point, PointData};
-use cgmath::{Matrix4, Vector3, Vector4};
+use cgmath::{InnerSpace, Matrix4, Vector3, Vector4};
 use collision::Aabb3;
+use crossbeam::channel;
 use fnv::FnvHashMap;
-use std::sync::mpsc; // should probably use crossbeam
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
 
 /// size for batch
 pub const NUM_POINTS_PER_BATCH: usize = 500_000;
 
+/// How long a worker's send to the batch channel waits before it wakes up to
+/// recheck cancellation, rather than blocking indefinitely or busy-spinning.
+const SEND_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone)]
 pub enum PointLocation {
@@ -19,6 +28,9 @@ pub enum PointLocation {
     Frustum(Matrix4<f64>),
     Obb(Obb<f64>),
     OrientedBeam(OrientedBeam<f64>),
+    /// The `k` points closest to `center`, found via a best-first traversal
+    /// of the octree rather than a culling predicate evaluated per point.
+    NearestNeighbors { center: Vector3<f64>, k: usize },
 }
 
 #[derive(Clone, Debug)]
@@ -36,12 +48,34 @@ impl PointQuery {
             PointLocation::Frustum(matrix) => Box::new(octree::Frustum::new(*matrix)),
             PointLocation::Obb(obb) => Box::new(obb.clone()),
             PointLocation::OrientedBeam(beam) => Box::new(beam.clone()),
+            // Never actually applied: `BatchIterator::try_for_each_batch`
+            // dispatches this variant to `nearest_neighbor::nearest_neighbors`
+            // instead of the generic node-enumeration + per-point-culling path.
+            PointLocation::NearestNeighbors { .. } => return Box::new(AllPoints {}),
         };
         match &self.global_from_local {
             Some(global_from_local) => culling.transform(&global_from_local),
             None => culling,
         }
     }
+
+    /// `center` transformed into the octree's native coordinate frame, for
+    /// `PointLocation::NearestNeighbors`. `center` itself is in local
+    /// coordinates (the same convention `Aabb`/`Obb` locations use), and
+    /// `nearest_neighbor::nearest_neighbors` works in native coordinates
+    /// (it compares against `point.position` and `octree.bounding_box`
+    /// directly), so this applies `global_from_local` — the same direction
+    /// baseline culling uses to move a local region into native space —
+    /// rather than its inverse.
+    pub fn nearest_neighbors_center_native(&self) -> Option<Vector3<f64>> {
+        match &self.location {
+            PointLocation::NearestNeighbors { center, .. } => Some(match &self.global_from_local {
+                Some(global_from_local) => global_from_local * center,
+                None => *center,
+            }),
+            _ => None,
+        }
+    }
 }
 /// current implementation of the stream of points used in BatchIterator
 struct PointStream<'a, F>
@@ -129,6 +163,11 @@ pub struct BatchIterator<'a> {
     octrees: Vec<&'a Octree>,
     point_location: &'a PointQuery,
     batch_size: usize,
+    io_engine: Box<dyn IoEngine<'a> + 'a>,
+    // Owned by the iterator and reused across calls: building a
+    // `rayon::ThreadPool` spins up OS threads, which is too expensive to
+    // redo on every `try_for_each_batch` call.
+    pool: rayon::ThreadPool,
 }
 
 impl<'a> BatchIterator<'a> {
@@ -136,12 +175,44 @@ impl<'a> BatchIterator<'a> {
         octrees: Vec<&'a octree::Octree>,
         point_location: &'a PointQuery,
         batch_size: usize,
-    ) -> Self {
-        BatchIterator {
+    ) -> Result<Self> {
+        Self::new_with_io_engine(
             octrees,
             point_location,
             batch_size,
-        }
+            Box::new(SyncIoEngine::new()),
+        )
+    }
+
+    /// Like `new`, but lets the caller pick how node blocks get read off
+    /// storage, e.g. a vectored engine for queries that touch many nodes.
+    pub fn new_with_io_engine(
+        octrees: Vec<&'a octree::Octree>,
+        point_location: &'a PointQuery,
+        batch_size: usize,
+        io_engine: Box<dyn IoEngine<'a> + 'a>,
+    ) -> Result<Self> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .build()
+            .chain_err(|| "could not build thread pool for BatchIterator")?;
+        Ok(BatchIterator {
+            octrees,
+            point_location,
+            batch_size,
+            io_engine,
+            pool,
+        })
+    }
+
+    /// Overrides how many worker threads `try_for_each_batch` processes
+    /// nodes with, rebuilding the owned thread pool. Defaults to rayon's
+    /// global thread pool size.
+    pub fn set_num_threads(&mut self, num_threads: usize) -> Result<&mut Self> {
+        self.pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(num_threads)
+            .build()
+            .chain_err(|| "could not build thread pool for BatchIterator")?;
+        Ok(self)
     }
 
     /// compute a function while iterating on a batch of points
@@ -149,6 +220,10 @@ impl<'a> BatchIterator<'a> {
     where
         F: FnMut(PointData) -> Result<()>,
     {
+        if let PointLocation::NearestNeighbors { k, .. } = &self.point_location.location {
+            return self.try_for_each_batch_nearest_neighbors(*k, func);
+        }
+
         //TODO(catevita): mutable function parallelization
         let local_from_global = self
             .point_location
@@ -167,37 +242,164 @@ impl<'a> BatchIterator<'a> {
             .collect();
         let pl = &self.point_location;
         let bs = self.batch_size;
-        crossbeam::scope(|s| {
-            let (tx, rx) = mpsc::sync_channel(100);
-            for (node_id, octree) in node_id_vec {
+        let io_batch_size = self.io_engine.get_batch_size();
+        let io_engine = &self.io_engine;
+        let pool = &self.pool;
+
+        let (tx, rx) = channel::bounded(100);
+        // Sticky once set: queued workers skip their batch instead of running
+        // it, and in-flight ones stop sending further batches, so the first
+        // error out of `func` wins instead of being dropped or racing a panic.
+        let cancelled = AtomicBool::new(false);
+        // The first real error seen anywhere (a bad node read or a point
+        // stream failure), so it can be propagated out instead of being
+        // dropped on the floor the way the old per-thread `Err(_) => ()` did.
+        let first_error: Mutex<Option<Error>> = Mutex::new(None);
+        let report_error = |err: Error| {
+            {
+                let mut guard = first_error.lock().unwrap();
+                if guard.is_none() {
+                    *guard = Some(err);
+                }
+            }
+            // Set only after the error is stored, so a worker that sees
+            // `cancelled` cannot get its own "cancelled" error in first.
+            cancelled.store(true, Ordering::Release);
+        };
+
+        pool.scope(|s| {
+            for node_id_chunk in node_id_vec.chunks(io_batch_size) {
+                if cancelled.load(Ordering::Acquire) {
+                    break;
+                }
+                // The read (and, for most engines, the decode) happens inside
+                // the spawned task so it runs on the pool's worker threads,
+                // not serially on the thread driving this loop.
+                let locations: Vec<NodeLocation> = node_id_chunk
+                    .iter()
+                    .map(|&(id, octree)| NodeLocation {
+                        id,
+                        octree,
+                        point_query: pl,
+                    })
+                    .collect();
                 let tx_thread = tx.clone();
                 let local_from_global_thread = local_from_global.clone();
+                let cancelled = &cancelled;
+                let report_error = &report_error;
                 s.spawn(move |_| {
-                    let point_iterator = octree.points_in_node(pl, node_id);
-                    let mut send_func = |batch| { 
-                        std::thread::sleep(std::time::Duration::from_secs(1));
-                        println!("Sending");
-                        let send_result = tx_thread.send(batch);
-                        // TODO: Map send_result to our own error type :(
-                        if send_result.is_err() {
-                            Err(ErrorKind::Grpc.into())
-                        } else {
-                            Ok(())
+                    if cancelled.load(Ordering::Acquire) {
+                        return;
+                    }
+                    let mut blocks: Vec<Block> = locations
+                        .into_iter()
+                        .map(|location| Block {
+                            location,
+                            points: Ok(Vec::new()),
+                        })
+                        .collect();
+                    if let Err(err) = io_engine.read_many(&mut blocks) {
+                        report_error(err);
+                        return;
+                    }
+                    for block in blocks {
+                        if cancelled.load(Ordering::Acquire) {
+                            return;
                         }
-                    };
-                    let mut point_stream = PointStream::new(bs, local_from_global_thread, &mut send_func);
+                        let points = match block.points {
+                            Ok(points) => points,
+                            Err(err) => {
+                                report_error(err);
+                                continue;
+                            }
+                        };
+                        let tx_thread = tx_thread.clone();
+                        // A plain blocking `send` would park a worker for good
+                        // if `func` errors and the consumer stops draining
+                        // `rx` while this send is in flight. `send_timeout`
+                        // still parks the thread (no busy-wait) but wakes up
+                        // periodically to recheck `cancelled` instead of
+                        // blocking forever.
+                        let mut send_func = |mut batch| loop {
+                            if cancelled.load(Ordering::Acquire) {
+                                return Err(Error::from("try_for_each_batch cancelled"));
+                            }
+                            match tx_thread.send_timeout(batch, SEND_POLL_INTERVAL) {
+                                Ok(()) => return Ok(()),
+                                Err(channel::SendTimeoutError::Timeout(b)) => batch = b,
+                                Err(channel::SendTimeoutError::Disconnected(_)) => {
+                                    return Err(Error::from("receiver disconnected"));
+                                }
+                            }
+                        };
+                        let mut point_stream = PointStream::new(
+                            bs,
+                            local_from_global_thread.clone(),
+                            &mut send_func,
+                        );
 
-                    for point in point_iterator {
-                        match point_stream.push_point_and_callback(point)  {
-                            Ok(()) => (),
-                            Err(err) => return (),
+                        for point in points {
+                            if let Err(err) = point_stream.push_point_and_callback(point) {
+                                report_error(err);
+                                break;
+                            }
+                        }
+                        if let Err(err) = point_stream.callback() {
+                            report_error(err);
                         }
                     }
-                    point_stream.callback().unwrap();
                 });
             }
-            rx.iter().try_for_each(func)
-        }).map_err(|e| {println!("Map_err"); e})
-        .expect("Point iterator thread panicked")
+            drop(tx);
+            rx.into_iter().try_for_each(|batch| {
+                func(batch).map_err(|err| {
+                    cancelled.store(true, Ordering::Release);
+                    err
+                })
+            })
+        })?;
+
+        match first_error.into_inner().unwrap() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// The `PointLocation::NearestNeighbors` path: run the best-first search
+    /// over every octree, merge the per-octree survivors down to the global
+    /// `k` closest, and emit them as a single distance-sorted batch.
+    fn try_for_each_batch_nearest_neighbors<F>(&mut self, k: usize, mut func: F) -> Result<()>
+    where
+        F: FnMut(PointData) -> Result<()>,
+    {
+        let center = self
+            .point_location
+            .nearest_neighbors_center_native()
+            .expect("try_for_each_batch_nearest_neighbors called without a NearestNeighbors location");
+        let local_from_global = self
+            .point_location
+            .global_from_local
+            .clone()
+            .map(|t| t.inverse());
+
+        let mut survivors: Vec<(f64, Point)> = self
+            .octrees
+            .iter()
+            .flat_map(|&octree| nearest_neighbor::nearest_neighbors(octree, center, k))
+            .map(|point| ((point.position - center).magnitude2(), point))
+            .collect();
+        survivors.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+        survivors.truncate(k);
+
+        let mut send_func = |batch| func(batch);
+        let mut point_stream = PointStream::new(
+            survivors.len().max(1),
+            local_from_global,
+            &mut send_func,
+        );
+        for (_, point) in survivors {
+            point_stream.push_point_and_callback(point)?;
+        }
+        point_stream.callback()
     }
 }
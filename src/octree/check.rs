@@ -0,0 +1,136 @@
+use crate::octree::batch_iterator::{PointLocation, PointQuery};
+use crate::octree::{NodeId, Octree};
+use crate::{LayerData, Point};
+use cgmath::Point3;
+use collision::{Aabb, Aabb3};
+use rayon::prelude::*;
+
+/// A single structural or data problem found on one node.
+#[derive(Debug, Clone, PartialEq)]
+pub enum NodeCheckError {
+    /// The node's stored checksum does not match its on-disk data.
+    ChecksumMismatch,
+    /// A point's position falls outside the node's own cell.
+    PointOutsideCell { position: Point3<f64> },
+    /// The node's bounding box is not contained within its parent's.
+    ChildAabbOutsideParent,
+    /// A layer's length does not match the number of points in the node.
+    LayerLengthMismatch {
+        layer: String,
+        num_points: usize,
+        layer_len: usize,
+    },
+}
+
+/// The outcome of checking every node of an octree: which nodes, if any,
+/// failed which checks. Corrupt/mismatched nodes are collected rather than
+/// aborting the walk on the first failure.
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    pub num_nodes_checked: usize,
+    pub corrupt_nodes: Vec<(NodeId, Vec<NodeCheckError>)>,
+}
+
+impl CheckReport {
+    pub fn is_ok(&self) -> bool {
+        self.corrupt_nodes.is_empty()
+    }
+}
+
+fn aabb_contains_aabb(outer: &Aabb3<f64>, inner: &Aabb3<f64>) -> bool {
+    outer.contains(&inner.min) && outer.contains(&inner.max)
+}
+
+fn check_layer_lengths(num_points: usize, layers: &[(&str, usize)]) -> Vec<NodeCheckError> {
+    layers
+        .iter()
+        .filter(|&&(_, layer_len)| layer_len != num_points)
+        .map(|&(layer, layer_len)| NodeCheckError::LayerLengthMismatch {
+            layer: layer.to_string(),
+            num_points,
+            layer_len,
+        })
+        .collect()
+}
+
+/// Checks a single node: its checksum, that its cell is contained within its
+/// parent's, that every point actually lies inside its cell, and that its
+/// layers (e.g. `color`/`intensity`, mirroring `PointStream::callback`'s
+/// layer layout) agree in length with its positions.
+fn check_node(octree: &Octree, node_id: NodeId) -> Vec<NodeCheckError> {
+    let mut errors = Vec::new();
+
+    // Only asserted when a checksum was actually stored for this node;
+    // older or partially-written octrees may not have one, and that is not
+    // itself a corruption.
+    if let Some(stored_checksum) = octree.stored_checksum(&node_id) {
+        if octree.compute_checksum(&node_id) != stored_checksum {
+            errors.push(NodeCheckError::ChecksumMismatch);
+        }
+    }
+
+    let cell = octree.bounding_box(&node_id);
+    if let Some(parent_id) = node_id.parent() {
+        let parent_cell = octree.bounding_box(&parent_id);
+        if !aabb_contains_aabb(&parent_cell, &cell) {
+            errors.push(NodeCheckError::ChildAabbOutsideParent);
+        }
+    }
+
+    let query = PointQuery {
+        location: PointLocation::AllPoints(),
+        global_from_local: None,
+    };
+    let mut num_points = 0;
+    for point in octree.points_in_node(&query, node_id) {
+        num_points += 1;
+        let position = Point3::from_vec(point.position);
+        if !cell.contains(&position) {
+            errors.push(NodeCheckError::PointOutsideCell { position });
+        }
+    }
+
+    // Layer lengths as actually stored on disk, independent of what
+    // `points_in_node` happens to decode, so a layer that is short or
+    // missing relative to `position` shows up here instead of silently
+    // matching `num_points` by construction.
+    let raw_layer_lens = octree.raw_layer_lengths(&node_id);
+    let layer_lens: Vec<(&str, usize)> = raw_layer_lens
+        .iter()
+        .map(|(layer, layer_len)| (layer.as_str(), *layer_len))
+        .collect();
+    errors.extend(check_layer_lengths(num_points, &layer_lens));
+
+    errors
+}
+
+/// Walks every node of `octree` in parallel, reusing the same node
+/// enumeration `BatchIterator` relies on, and verifies each one against its
+/// stored checksum plus structural invariants. Returns a report of every
+/// corrupt or mismatched node rather than aborting on the first failure, so
+/// damaged or partially-written octrees can be diagnosed before a viewer
+/// tries to stream them.
+pub fn check(octree: &Octree) -> CheckReport {
+    let query = PointQuery {
+        location: PointLocation::AllPoints(),
+        global_from_local: None,
+    };
+    let node_ids: Vec<NodeId> = octree.nodes_in_location(&query).collect();
+
+    let corrupt_nodes: Vec<(NodeId, Vec<NodeCheckError>)> = node_ids
+        .par_iter()
+        .filter_map(|&node_id| {
+            let errors = check_node(octree, node_id);
+            if errors.is_empty() {
+                None
+            } else {
+                Some((node_id, errors))
+            }
+        })
+        .collect();
+
+    CheckReport {
+        num_nodes_checked: node_ids.len(),
+        corrupt_nodes,
+    }
+}
@@ -0,0 +1,204 @@
+use crate::octree::batch_iterator::{PointLocation, PointQuery};
+use crate::octree::node::NodeId;
+use crate::octree::Octree;
+use crate::Point;
+use cgmath::{InnerSpace, Vector3};
+use collision::Aabb3;
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+
+/// Squared distance from `point` to the closest point of `aabb` (zero if
+/// `point` is inside). This is what orders node visitation and what lets a
+/// node be pruned once it cannot hold anything closer than the current
+/// worst of the `k` best candidates.
+fn squared_distance_to_aabb(aabb: &Aabb3<f64>, point: Vector3<f64>) -> f64 {
+    let clamp = |value: f64, min: f64, max: f64| value.max(min).min(max);
+    let closest = Vector3::new(
+        clamp(point.x, aabb.min.x, aabb.max.x),
+        clamp(point.y, aabb.min.y, aabb.max.y),
+        clamp(point.z, aabb.min.z, aabb.max.z),
+    );
+    (closest - point).magnitude2()
+}
+
+/// A candidate ordered by squared distance from the query center, smallest
+/// first when used behind `Reverse` in a min-queue, largest first (the
+/// current worst) when used directly in a bounded max-heap.
+struct ByDistance<T> {
+    squared_distance: f64,
+    value: T,
+}
+
+impl<T> PartialEq for ByDistance<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.squared_distance == other.squared_distance
+    }
+}
+impl<T> Eq for ByDistance<T> {}
+impl<T> PartialOrd for ByDistance<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl<T> Ord for ByDistance<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.squared_distance
+            .partial_cmp(&other.squared_distance)
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+/// Finds the `k` points of `octree` closest to `center`, in the octree's
+/// own (native) coordinate frame — callers in local coordinates must
+/// transform `center` themselves, matching `Aabb`/`Obb` locations. This is
+/// what `PointLocation::NearestNeighbors` resolves to: a best-first
+/// traversal keeps a min-priority queue of nodes ordered by
+/// `squared_distance_to_aabb`, plus a bounded max-heap of the `k` best
+/// point candidates seen so far (its top is always the current worst).
+///
+/// The queue is seeded from `Octree::nodes_in_location` rather than walking
+/// parent/child links directly. For `AllPoints` that enumerates every node,
+/// so every node holding points is already in the queue, sorted by
+/// distance, before the loop starts, and a node is dropped — along with
+/// everything still behind it in the queue — as soon as its minimum
+/// possible distance exceeds the heap's worst once the heap holds `k`
+/// candidates. Each node is popped at most once and only its own points
+/// are scanned; no de-duplication of points is done.
+pub fn nearest_neighbors(octree: &Octree, center: Vector3<f64>, k: usize) -> Vec<Point> {
+    if k == 0 {
+        return Vec::new();
+    }
+
+    let all_points_query = PointQuery {
+        location: PointLocation::AllPoints(),
+        global_from_local: None,
+    };
+
+    let mut node_queue: BinaryHeap<Reverse<ByDistance<NodeId>>> = octree
+        .nodes_in_location(&all_points_query)
+        .map(|node_id| {
+            let squared_distance = squared_distance_to_aabb(&octree.bounding_box(&node_id), center);
+            Reverse(ByDistance {
+                squared_distance,
+                value: node_id,
+            })
+        })
+        .collect();
+
+    let mut point_heap: BinaryHeap<ByDistance<Point>> = BinaryHeap::new();
+
+    while let Some(Reverse(ByDistance {
+        squared_distance: node_distance,
+        value: node_id,
+    })) = node_queue.pop()
+    {
+        if point_heap.len() == k {
+            if let Some(worst) = point_heap.peek() {
+                if node_distance > worst.squared_distance {
+                    break;
+                }
+            }
+        }
+
+        for point in octree.points_in_node(&all_points_query, node_id) {
+            let squared_distance = (point.position - center).magnitude2();
+            point_heap.push(ByDistance {
+                squared_distance,
+                value: point,
+            });
+            if point_heap.len() > k {
+                point_heap.pop();
+            }
+        }
+    }
+
+    // `into_sorted_vec` is already ascending by `squared_distance`.
+    point_heap
+        .into_sorted_vec()
+        .into_iter()
+        .map(|entry| entry.value)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::Point3;
+
+    fn aabb(min: [f64; 3], max: [f64; 3]) -> Aabb3<f64> {
+        Aabb3::new(Point3::from(min), Point3::from(max))
+    }
+
+    #[test]
+    fn squared_distance_to_aabb_zero_when_inside() {
+        let cell = aabb([0.0, 0.0, 0.0], [1.0, 1.0, 1.0]);
+        assert_eq!(squared_distance_to_aabb(&cell, Vector3::new(0.5, 0.5, 0.5)), 0.0);
+    }
+
+    #[test]
+    fn squared_distance_to_aabb_zero_on_boundary() {
+        let cell = aabb([0.0, 0.0, 0.0], [1.0, 1.0, 1.0]);
+        assert_eq!(squared_distance_to_aabb(&cell, Vector3::new(1.0, 0.5, 0.5)), 0.0);
+    }
+
+    #[test]
+    fn squared_distance_to_aabb_matches_nearest_face() {
+        let cell = aabb([0.0, 0.0, 0.0], [1.0, 1.0, 1.0]);
+        // 2 units past the max-x face, level with the cell otherwise.
+        assert_eq!(
+            squared_distance_to_aabb(&cell, Vector3::new(3.0, 0.5, 0.5)),
+            4.0
+        );
+    }
+
+    #[test]
+    fn squared_distance_to_aabb_matches_nearest_corner() {
+        let cell = aabb([0.0, 0.0, 0.0], [1.0, 1.0, 1.0]);
+        // 3-4-5 triangle out past the (1, 1, 1) corner in the xy-plane.
+        assert_eq!(
+            squared_distance_to_aabb(&cell, Vector3::new(4.0, 5.0, 1.0)),
+            9.0 + 16.0
+        );
+    }
+
+    #[test]
+    fn by_distance_min_heap_via_reverse_pops_closest_first() {
+        let mut queue: BinaryHeap<Reverse<ByDistance<&str>>> = BinaryHeap::new();
+        queue.push(Reverse(ByDistance {
+            squared_distance: 9.0,
+            value: "far",
+        }));
+        queue.push(Reverse(ByDistance {
+            squared_distance: 1.0,
+            value: "near",
+        }));
+        queue.push(Reverse(ByDistance {
+            squared_distance: 4.0,
+            value: "mid",
+        }));
+
+        assert_eq!(queue.pop().unwrap().0.value, "near");
+        assert_eq!(queue.pop().unwrap().0.value, "mid");
+        assert_eq!(queue.pop().unwrap().0.value, "far");
+    }
+
+    #[test]
+    fn by_distance_max_heap_keeps_worst_on_top_for_bounded_k() {
+        // Mirrors how `nearest_neighbors` bounds `point_heap` to size `k`:
+        // push, then pop once capacity is exceeded, to keep the `k` closest.
+        let mut heap: BinaryHeap<ByDistance<&str>> = BinaryHeap::new();
+        let k = 2;
+        for (squared_distance, value) in [(4.0, "mid"), (1.0, "near"), (9.0, "far")] {
+            heap.push(ByDistance {
+                squared_distance,
+                value,
+            });
+            if heap.len() > k {
+                heap.pop();
+            }
+        }
+        let mut survivors: Vec<&str> = heap.into_sorted_vec().into_iter().map(|e| e.value).collect();
+        survivors.sort();
+        assert_eq!(survivors, vec!["mid", "near"]);
+    }
+}
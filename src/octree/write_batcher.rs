@@ -0,0 +1,134 @@
+use crate::errors::*;
+use crate::octree::batch_iterator::NUM_POINTS_PER_BATCH;
+use crate::octree::node_writer::NodeWriter;
+use crate::octree::{NodeId, OctreeMeta};
+use crate::{LayerData, PointData};
+use cgmath::{Vector3, Vector4};
+use fnv::FnvHashMap;
+
+/// Points accumulated for a single node, waiting to be written out. Mirrors
+/// the position/color/intensity layout `PointStream` reads, just on the
+/// write side.
+#[derive(Default)]
+struct NodeBuffer {
+    position: Vec<Vector3<f64>>,
+    color: Vec<Vector4<u8>>,
+    intensity: Vec<f32>,
+}
+
+impl NodeBuffer {
+    fn is_empty(&self) -> bool {
+        self.position.is_empty()
+    }
+}
+
+/// Write-side counterpart to `PointStream`/`BatchIterator`: accepts the
+/// `PointData` batches a `PointQuery` produces over one or more octrees and
+/// incrementally builds a new on-disk octree out of them, so "crop to an
+/// AABB/OBB/NearestNeighbors and save" can be a first-class operation
+/// instead of something every caller has to reimplement.
+///
+/// `NodeWriter::write_points` is assumed to append to a node's on-disk
+/// stream rather than overwrite it, so a node can be written to more than
+/// once as it fills back up between flushes. That assumption, like the
+/// per-node flush threshold below, is not exercised against a real
+/// `NodeWriter` anywhere in this tree — there is no test fixture for one.
+pub struct WriteBatcher {
+    meta: OctreeMeta,
+    writer: NodeWriter,
+    points_per_node: FnvHashMap<NodeId, NodeBuffer>,
+    // Whether `color`/`intensity` layers are present, fixed by whichever
+    // `add` call sees them first. `PointStream` always emits `color` and
+    // emits `intensity` only when the source octree(s) have it, so this is
+    // expected to be uniform across every batch of a single query; a later
+    // `add` call that disagrees would otherwise silently write a node's
+    // `color`/`intensity` vector shorter than its `position`.
+    has_color: Option<bool>,
+    has_intensity: Option<bool>,
+}
+
+impl WriteBatcher {
+    pub fn new(meta: OctreeMeta, writer: NodeWriter) -> Self {
+        WriteBatcher {
+            meta,
+            writer,
+            points_per_node: FnvHashMap::default(),
+            has_color: None,
+            has_intensity: None,
+        }
+    }
+
+    /// Buffers `point_data`, assigning each point to its target node and
+    /// flushing only the individual nodes that cross `NUM_POINTS_PER_BATCH`
+    /// points along the way, so a node that never fills up stays buffered
+    /// (and is only ever written once) until `finish`.
+    pub fn add(&mut self, point_data: PointData) -> Result<()> {
+        let color = match point_data.layers.get("color") {
+            Some(LayerData::U8Vec4(color)) => Some(color),
+            _ => None,
+        };
+        let intensity = match point_data.layers.get("intensity") {
+            Some(LayerData::F32(intensity)) => Some(intensity),
+            _ => None,
+        };
+        if *self.has_color.get_or_insert(color.is_some()) != color.is_some() {
+            return Err(Error::from(
+                "WriteBatcher: color layer presence changed across add() calls",
+            ));
+        }
+        if *self.has_intensity.get_or_insert(intensity.is_some()) != intensity.is_some() {
+            return Err(Error::from(
+                "WriteBatcher: intensity layer presence changed across add() calls",
+            ));
+        }
+
+        for (i, &position) in point_data.position.iter().enumerate() {
+            let node_id = self.meta.node_id_containing(position);
+            let buffer = self.points_per_node.entry(node_id).or_default();
+            buffer.position.push(position);
+            if let Some(color) = color {
+                buffer.color.push(color[i]);
+            }
+            if let Some(intensity) = intensity {
+                buffer.intensity.push(intensity[i]);
+            }
+        }
+
+        let WriteBatcher {
+            writer,
+            points_per_node,
+            ..
+        } = self;
+        for (node_id, buffer) in points_per_node.iter_mut() {
+            if buffer.position.len() >= NUM_POINTS_PER_BATCH {
+                writer.write_points(node_id, &buffer.position, &buffer.color, &buffer.intensity)?;
+                *buffer = NodeBuffer::default();
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes out every node still holding buffered points, however few.
+    fn flush(&mut self) -> Result<()> {
+        let WriteBatcher {
+            writer,
+            points_per_node,
+            ..
+        } = self;
+        for (node_id, buffer) in points_per_node.iter_mut() {
+            if buffer.is_empty() {
+                continue;
+            }
+            writer.write_points(node_id, &buffer.position, &buffer.color, &buffer.intensity)?;
+            *buffer = NodeBuffer::default();
+        }
+        Ok(())
+    }
+
+    /// Flushes any partially filled nodes and writes the octree metadata.
+    /// Call this once after the last `add`.
+    pub fn finish(mut self) -> Result<()> {
+        self.flush()?;
+        self.writer.write_octree_meta(&self.meta)
+    }
+}